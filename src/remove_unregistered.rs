@@ -1,47 +1,464 @@
-use rtorrent_xmlrpc_bindings::{multicall::d, multicall::f, Download, Result, Server};
+use regex::Regex;
+use rtorrent_xmlrpc_bindings::{multicall::d, multicall::f, Download, Result, Server, Tracker};
 use std::collections::HashSet;
+use std::io::Write;
 use std::path::Path;
 
+/// Which piece of download/tracker state a [`Rule`] inspects.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Field {
+    /// The download's overall status message (`d::MESSAGE`) — this is where rtorrent surfaces
+    /// tracker-reported failures like "Tracker: [Failure reason ...]".
+    Message,
+    /// The download's ratio (`d::RATIO`), in rtorrent's usual permille representation.
+    Ratio,
+    /// Seconds since the download completed (derived from `d::TIMESTAMP_FINISHED`).
+    Age,
+    /// Whether the download has finished (`d::COMPLETE`).
+    Completed,
+}
+
+/// How a [`Rule`]'s pattern should be compared against the field's value.
+#[derive(Clone, Debug)]
+enum Matcher {
+    /// Case-insensitive literal prefix match (the original hardcoded behavior).
+    Prefix(String),
+    /// Arbitrary regex match.
+    Regex(Regex),
+    /// Numeric comparison, for Ratio/Age/Completed fields: `<`, `<=`, `>`, `>=`, `=` followed by
+    /// an integer.
+    Numeric(std::cmp::Ordering, bool, i64),
+}
+
+/// A single reaper rule: if `matcher` matches `field`, the torrent is reaped and `name` is
+/// reported as the reason.
+#[derive(Clone, Debug)]
+struct Rule {
+    name: String,
+    field: Field,
+    matcher: Matcher,
+}
+
+impl Rule {
+    /// Parse one `name<TAB>field<TAB>matcher-kind<TAB>pattern` line from a rules file.
+    ///
+    /// Blank lines and lines starting with `#` are ignored.
+    fn parse_line(line: &str) -> Option<Rule> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let mut parts = line.splitn(4, '\t');
+        let name = parts.next().expect("name column").to_string();
+        let field = match parts.next().expect("field column") {
+            "message" => Field::Message,
+            "ratio" => Field::Ratio,
+            "age" => Field::Age,
+            "completed" => Field::Completed,
+            other => panic!("Unknown rule field '{}' in rule '{}'", other, name),
+        };
+        let kind = parts.next().expect("matcher-kind column");
+        let pattern = parts.next().expect("pattern column");
+
+        let matcher = match kind {
+            "prefix" => Matcher::Prefix(pattern.to_lowercase()),
+            "regex" => Matcher::Regex(
+                Regex::new(pattern).unwrap_or_else(|e| panic!("Bad regex in rule '{}': {}", name, e)),
+            ),
+            op @ ("<" | "<=" | ">" | ">=" | "=") => {
+                let value: i64 = pattern
+                    .parse()
+                    .unwrap_or_else(|e| panic!("Bad numeric pattern in rule '{}': {}", name, e));
+                let (ord, inclusive) = match op {
+                    "<" => (std::cmp::Ordering::Less, false),
+                    "<=" => (std::cmp::Ordering::Less, true),
+                    ">" => (std::cmp::Ordering::Greater, false),
+                    ">=" => (std::cmp::Ordering::Greater, true),
+                    "=" => (std::cmp::Ordering::Equal, false),
+                    _ => unreachable!(),
+                };
+                Matcher::Numeric(ord, inclusive, value)
+            }
+            other => panic!("Unknown matcher kind '{}' in rule '{}'", other, name),
+        };
+
+        Some(Rule { name, field, matcher })
+    }
+
+    /// Load rules from a tab-separated rules file, one rule per line.
+    fn load_file(path: &str) -> Vec<Rule> {
+        let contents = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Couldn't read rules file '{}': {}", path, e));
+        contents.lines().filter_map(Rule::parse_line).collect()
+    }
+
+    /// The rule set used when no `--rules-file` is given: the tool's original behavior, just
+    /// expressed as a rule.
+    fn defaults() -> Vec<Rule> {
+        vec![Rule {
+            name: "unregistered-torrent".to_string(),
+            field: Field::Message,
+            matcher: Matcher::Prefix("Tracker: [Failure reason \"Unregistered torrent".to_lowercase()),
+        }]
+    }
+
+    fn matches_str(&self, value: &str) -> bool {
+        match &self.matcher {
+            Matcher::Prefix(prefix) => value.to_lowercase().starts_with(prefix.as_str()),
+            Matcher::Regex(re) => re.is_match(value),
+            Matcher::Numeric(..) => panic!("Rule '{}' has a string field but a numeric matcher", self.name),
+        }
+    }
+
+    fn matches_num(&self, value: i64) -> bool {
+        match &self.matcher {
+            Matcher::Numeric(ord, inclusive, pattern) => {
+                value.cmp(pattern) == *ord || (*inclusive && value == *pattern)
+            }
+            _ => panic!("Rule '{}' has a numeric field but a string matcher", self.name),
+        }
+    }
+}
+
+/// How aggressively to reclaim on-disk data for a reaped torrent.
+///
+/// Modeled on libtorrent's `remove_torrent` option flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum DeleteMode {
+    /// Remove the watched/session .torrent files and all content (the historical behavior).
+    DeleteFiles,
+    /// Remove only the watched/session .torrent files; leave content on disk for seeding
+    /// elsewhere.
+    DeleteStateOnly,
+    /// Remove only incomplete (partially downloaded) content files; leave completed files and
+    /// the watched/session .torrent files alone.
+    DeletePartialOnly,
+}
+
+impl DeleteMode {
+    fn from_flag(flag: Option<&str>) -> DeleteMode {
+        match flag {
+            None | Some("delete_files") => DeleteMode::DeleteFiles,
+            Some("delete_state_only") => DeleteMode::DeleteStateOnly,
+            Some("delete_partial_only") => DeleteMode::DeletePartialOnly,
+            Some(other) => panic!("Unknown --delete-mode '{}'", other),
+        }
+    }
+}
+
+/// Parsed CLI configuration.
+///
+/// The xmlrpc URI is the only positional argument; everything else is an optional `--flag` or
+/// `--flag=value`, since the set of options has grown past what's comfortable positionally.
+struct Config {
+    uri: String,
+    delete_mode: DeleteMode,
+    rules: Vec<Rule>,
+    /// Run the full detection and path-resolution pipeline, but don't touch the filesystem.
+    dry_run: bool,
+    /// Where to write the one-JSON-record-per-torrent deletion report, if requested.
+    report_path: Option<String>,
+    /// Pause between torrents we actually reap; rtorrent can be brittle under back-to-back RPCs.
+    delay_ms: u64,
+    /// How many times to retry a failed XMLRPC call before giving up on that torrent.
+    max_retries: u32,
+    /// Base backoff between retries; attempt N waits `retry_backoff_ms * N`.
+    retry_backoff_ms: u64,
+    /// If set, reaped content (unless `delete_state_only`) and the watched/session .torrent files
+    /// (unless `delete_partial_only`) are moved here instead of being unlinked, giving an undo
+    /// window.
+    trash_dir: Option<String>,
+    /// With `trash_dir` set, purge trashed entries older than this many days on every run.
+    retention_days: Option<u64>,
+}
+
+impl Config {
+    fn from_args() -> Config {
+        let mut uri = None;
+        let mut delete_mode = None;
+        let mut rules_file = None;
+        let mut dry_run = false;
+        let mut report_path = None;
+        let mut delay_ms = 500;
+        let mut max_retries = 3;
+        let mut retry_backoff_ms = 500;
+        let mut trash_dir = None;
+        let mut retention_days = None;
+
+        for arg in std::env::args().skip(1) {
+            if let Some(v) = arg.strip_prefix("--delete-mode=") {
+                delete_mode = Some(DeleteMode::from_flag(Some(v)));
+            } else if let Some(v) = arg.strip_prefix("--rules-file=") {
+                rules_file = Some(v.to_string());
+            } else if let Some(v) = arg.strip_prefix("--report=") {
+                report_path = Some(v.to_string());
+            } else if let Some(v) = arg.strip_prefix("--delay-ms=") {
+                delay_ms = v.parse().unwrap_or_else(|e| panic!("Bad --delay-ms value '{}': {}", v, e));
+            } else if let Some(v) = arg.strip_prefix("--max-retries=") {
+                max_retries = v.parse().unwrap_or_else(|e| panic!("Bad --max-retries value '{}': {}", v, e));
+            } else if let Some(v) = arg.strip_prefix("--retry-backoff-ms=") {
+                retry_backoff_ms = v.parse().unwrap_or_else(|e| panic!("Bad --retry-backoff-ms value '{}': {}", v, e));
+            } else if let Some(v) = arg.strip_prefix("--trash-dir=") {
+                trash_dir = Some(v.to_string());
+            } else if let Some(v) = arg.strip_prefix("--retention-days=") {
+                retention_days = Some(v.parse().unwrap_or_else(|e| panic!("Bad --retention-days value '{}': {}", v, e)));
+            } else if arg == "--dry-run" {
+                dry_run = true;
+            } else if uri.is_none() {
+                uri = Some(arg);
+            } else {
+                panic!("Unexpected argument '{}'", arg);
+            }
+        }
+
+        Config {
+            uri: uri.expect("Pass an rtorrent xmlrpc URI as the first argument."),
+            delete_mode: delete_mode.unwrap_or(DeleteMode::DeleteFiles),
+            rules: match rules_file {
+                Some(path) => Rule::load_file(&path),
+                None => Rule::defaults(),
+            },
+            dry_run,
+            report_path,
+            delay_ms,
+            max_retries,
+            retry_backoff_ms,
+            trash_dir,
+            retention_days,
+        }
+    }
+
+    fn delay(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.delay_ms)
+    }
+
+    fn retry_backoff(&self, attempt: u32) -> std::time::Duration {
+        std::time::Duration::from_millis(self.retry_backoff_ms * attempt as u64)
+    }
+
+    fn trash_dir(&self) -> Option<std::path::PathBuf> {
+        self.trash_dir.as_ref().map(|d| std::path::PathBuf::from(shellexpand::tilde(d).into_owned()))
+    }
+}
+
+/// Call `f`, retrying up to `config.max_retries` times with linear backoff if it returns an
+/// error. Rtorrent's XMLRPC endpoint can hiccup transiently; this absorbs that without giving up
+/// on the whole run.
+fn with_retries<T>(config: &Config, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(v) => return Ok(v),
+            Err(e) => {
+                attempt += 1;
+                if attempt > config.max_retries {
+                    return Err(e);
+                }
+                println!("XMLRPC call failed (attempt {}/{}): {}; retrying", attempt, config.max_retries + 1, e);
+                std::thread::sleep(config.retry_backoff(attempt));
+            }
+        }
+    }
+}
+
 fn main() -> Result<()> {
-    let uri = std::env::args().nth(1)
-        .expect("Pass an rtorrent xmlrpc URI as the first argument.");
-    let handle = Server::new(&uri);
+    let config = Config::from_args();
+    let handle = Server::new(&config.uri);
+
+    if let (Some(trash_dir), Some(retention_days)) = (config.trash_dir(), config.retention_days) {
+        let retention = std::time::Duration::from_secs(retention_days * 24 * 60 * 60);
+        if let Err(e) = purge_trash(&trash_dir, retention, config.dry_run) {
+            println!("Error purging old trash from {}: {}", trash_dir.display(), e);
+        }
+    }
 
-    // For all torrents in the "default" view, get their infohash, name, and any tracker message
-    // reported by rtorrent.
+    let mut report: Box<dyn Write> = match &config.report_path {
+        Some(path) => Box::new(
+            std::fs::File::create(path)
+                .unwrap_or_else(|e| panic!("Couldn't create report file '{}': {}", path, e)),
+        ),
+        None => Box::new(std::io::stdout()),
+    };
+
+    // For all torrents in the "default" view, get the fields our rules can match against.
     let query = d::MultiBuilder::new(&handle, "default")
         .call(d::HASH)
         .call(d::NAME)
-        .call(d::MESSAGE);
+        .call(d::MESSAGE)
+        .call(d::RATIO)
+        .call(d::COMPLETE)
+        .call(d::TIMESTAMP_FINISHED);
 
-    for (dlhash, name, msg) in query.invoke()? {
-        let msg = msg.to_lowercase();
-        if !msg.starts_with(&"Tracker: [Failure reason \"Unregistered torrent".to_lowercase()) {
-            continue;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    let rows = with_retries(&config, || query.invoke())?;
+
+    // Built once per run, not once per reaped torrent: re-querying every download's content paths
+    // for every reap would turn one pass into O(n^2) XMLRPC traffic on a large session.
+    let content_index = with_retries(&config, || build_content_path_index(&handle))?;
+
+    for (dlhash, name, msg, ratio, complete, finished_at) in rows {
+        let age = if finished_at > 0 { now - finished_at } else { 0 };
+
+        let reaped = reap_one(&handle, &dlhash, &name, &msg, ratio, complete, age, &config, &content_index, &mut *report);
+
+        if reaped {
+            // Rtorrent can be kind of brittle; try not to crash it.
+            std::thread::sleep(config.delay());
         }
+    }
+
+    Ok(())
+}
 
-        let dl = Download::from_hash(&handle, &dlhash);
-        let trackers = dl.trackers()?;
-        let tracker = &trackers[0];
+/// Evaluate and, if warranted, reap a single download. Errors along the way (an RPC failure, a
+/// filesystem permission error) are logged and swallowed here, so one bad download can't abort the
+/// whole run; an unparseable tracker URL or a missing tracker is not an error at all, and is
+/// reported as the raw URL or `"-"` respectively. Returns `true` if the torrent
+/// was matched and a deletion was attempted (whether or not it fully succeeded).
+fn reap_one(handle: &Server, dlhash: &str, name: &str, msg: &str, ratio: i64, complete: i64, age: i64, config: &Config, content_index: &ContentPathIndex, report: &mut dyn Write) -> bool {
+    let dl = Download::from_hash(handle, dlhash);
 
-        let url = tracker.url()?;
-        let url = match url::Url::parse(&url) {
-            Ok(x) => x,
-            Err(x) => {
-                panic!("Invalid tracker url '{}': {}", url, x);
+    let matched = match with_retries(config, || find_matching_rule(&dl, msg, ratio, complete, age, &config.rules)) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}: error evaluating reaper rules: {}", name, e);
+            return false;
+        }
+    };
+
+    let (rule_name, shorturl) = match matched {
+        Some(m) => m,
+        None => return false,
+    };
+
+    println!("Reaped[{}] (rule: {}):\t{}", shorturl, rule_name, name);
+
+    let record = match with_retries(config, || {
+        delete(handle, Download::from_hash(handle, dlhash), dlhash, &rule_name, &shorturl, config.delete_mode, config.dry_run, config.trash_dir().as_deref(), content_index)
+    }) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("{}: error deleting: {}", name, e);
+            // Still emit a record: operators watching --report for failures need to see this one
+            // too, not just the ones that got far enough to build a DeletionPlan.
+            DeletionRecord {
+                infohash: dlhash.to_string(),
+                name: name.to_string(),
+                tracker_host: shorturl,
+                rule: rule_name,
+                dry_run: config.dry_run,
+                files: Vec::new(),
+                directories: Vec::new(),
+                total_bytes: 0,
+                error: Some(e.to_string()),
             }
-        };
-        let shorturl = url.host_str().unwrap();
+        }
+    };
 
-        println!("Unregistered[{}]:\t{}", shorturl, name);
+    writeln!(report, "{}", record.to_json()).expect("failed to write deletion report");
+    true
+}
 
-        delete(&handle, dl)?;
+/// Check `dl` against every rule in `rules`, in order, returning the name of the first rule that
+/// fires along with a tracker host to report (the first tracker's host for `message` rules, `"-"`
+/// for the others). `msg`, `ratio`, `complete`, and `age` are the download-level field values already
+/// fetched for this download.
+fn find_matching_rule(dl: &Download, msg: &str, ratio: i64, complete: i64, age: i64, rules: &[Rule]) -> Result<Option<(String, String)>> {
+    for rule in rules {
+        match rule.field {
+            Field::Message => {
+                if rule.matches_str(msg) {
+                    // d::MESSAGE is a download-level field, so it isn't tied to any one tracker;
+                    // report the first tracker's host, matching the tool's historical behavior.
+                    let host = match dl.trackers()?.first() {
+                        Some(tracker) => tracker_host(tracker)?,
+                        None => "-".to_string(),
+                    };
+                    return Ok(Some((rule.name.clone(), host)));
+                }
+            }
+            Field::Ratio => {
+                if rule.matches_num(ratio) {
+                    return Ok(Some((rule.name.clone(), "-".to_string())));
+                }
+            }
+            Field::Completed => {
+                if rule.matches_num(complete) {
+                    return Ok(Some((rule.name.clone(), "-".to_string())));
+                }
+            }
+            Field::Age => {
+                if rule.matches_num(age) {
+                    return Ok(Some((rule.name.clone(), "-".to_string())));
+                }
+            }
+        }
+    }
 
-        // Rtorrent can be kind of brittle; try not to crash it.
-        std::thread::sleep(std::time::Duration::from_millis(500));
+    Ok(None)
+}
+
+/// Best-effort hostname for a tracker, for human-readable logging.
+fn tracker_host(tracker: &Tracker) -> Result<String> {
+    let url = tracker.url()?;
+    Ok(url::Url::parse(&url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or(url))
+}
+
+/// One JSON record per reaped torrent, analogous to the "deleted" events other clients emit, so
+/// reaping decisions can be audited (especially under `--dry-run`) or fed into monitoring.
+struct DeletionRecord {
+    infohash: String,
+    name: String,
+    tracker_host: String,
+    rule: String,
+    dry_run: bool,
+    files: Vec<String>,
+    directories: Vec<String>,
+    total_bytes: u64,
+    error: Option<String>,
+}
+
+impl DeletionRecord {
+    fn to_json(&self) -> String {
+        let files = self.files.iter().map(|f| json_string(f)).collect::<Vec<_>>().join(",");
+        let dirs = self.directories.iter().map(|d| json_string(d)).collect::<Vec<_>>().join(",");
+        let status = match &self.error {
+            Some(e) => format!("\"error\",\"error\":{}", json_string(e)),
+            None => "\"ok\"".to_string(),
+        };
+        format!(
+            "{{\"infohash\":{},\"name\":{},\"tracker_host\":{},\"rule\":{},\"dry_run\":{},\"files\":[{}],\"directories\":[{}],\"total_bytes\":{},\"status\":{}}}",
+            json_string(&self.infohash), json_string(&self.name), json_string(&self.tracker_host),
+            json_string(&self.rule), self.dry_run, files, dirs, self.total_bytes, status,
+        )
     }
+}
 
-    Ok(())
+/// Escape `s` as a JSON string literal, including the surrounding quotes.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
 
 /// Unregister this download from rtorrent and remove associated files.
@@ -49,9 +466,12 @@ fn main() -> Result<()> {
 /// The assumption is that rtorrent loaded the download from a watched directory, so removing the
 /// watched .torrent file will unregister the download from rtorrent.
 ///
+/// In `dry_run` mode, every RPC query and filesystem stat/canonicalize below still happens, but no
+/// file or directory is actually removed; the returned record describes what would have happened.
+///
 /// This performs some RPC queries and could return an error if there was a problem communicating
 /// with the rtorrent XMLRPC API endpoint.
-fn delete(handle: &Server, dl: Download) -> Result<()> {
+fn delete(handle: &Server, dl: Download, dlhash: &str, rule: &str, tracker_host: &str, mode: DeleteMode, dry_run: bool, trash_dir: Option<&Path>, content_index: &ContentPathIndex) -> Result<DeletionRecord> {
     let name = dl.name()?;
     let content_path_str = dl.base_path()?;
     let watched_tor_str = dl.tied_to_file()?;
@@ -60,13 +480,16 @@ fn delete(handle: &Server, dl: Download) -> Result<()> {
     let watched_tor = shellexpand::tilde(&watched_tor_str);
     let session_tor = shellexpand::tilde(&session_tor_str);
 
-    // Get the paths of all files associated with this download.
+    // Get the paths, sizes, and (for delete_partial_only) completion of all files associated with
+    // this download.
     let content_files = f::MultiBuilder::new(&handle, dl.sha1_hex(), None)
         .call(f::PATH)
+        .call(f::COMPLETED_CHUNKS)
+        .call(f::SIZE_CHUNKS)
+        .call(f::SIZE_BYTES)
         .invoke()?
-        // Convert Vec<(String,)> to Vec<String>.
         .into_iter()
-        .map(|(path,)| path)
+        .map(|(path, completed, size, size_bytes)| FileInfo { path, completed, size, size_bytes })
         .collect::<Vec<_>>();
 
     // content_files are relative to dl.directory(); however, for single-file torrents, we don't
@@ -76,47 +499,263 @@ fn delete(handle: &Server, dl: Download) -> Result<()> {
     assert_ne!(content_path, "/");
     assert!(content_path.len() > 1);
 
-    // Start removing torrent state, then content.
-    if let Err(e) = delete_from_filesystem(&watched_tor, &session_tor, &content_path, content_files.as_slice()) {
-        // Report removal errors, but squash them.
-        println!("{}: Got an error when deleting: {} (session {}, watch {})", name, e, session_tor, watched_tor);
+    // Cross-seeded content (the same data tied to more than one registered download) must not be
+    // deleted out from under the other download.
+    let shared = other_downloads_content_paths(content_index, dl.sha1_hex());
+
+    let mut record = DeletionRecord {
+        infohash: dlhash.to_string(),
+        name: name.clone(),
+        tracker_host: tracker_host.to_string(),
+        rule: rule.to_string(),
+        dry_run,
+        files: Vec::new(),
+        directories: Vec::new(),
+        total_bytes: 0,
+        error: None,
+    };
+
+    match delete_from_filesystem(mode, dry_run, trash_dir, dlhash, &watched_tor, &session_tor, &content_path, content_files.as_slice(), &shared) {
+        Ok(plan) => {
+            record.files = plan.files;
+            record.directories = plan.directories;
+            record.total_bytes = plan.total_bytes;
+            println!("Ok.");
+        }
+        Err(e) => {
+            // Report removal errors, but squash them.
+            println!("{}: Got an error when deleting: {} (session {}, watch {})", name, e, session_tor, watched_tor);
+            record.error = Some(e.to_string());
+        }
+    }
+    Ok(record)
+}
+
+/// Maps each registered download's infohash to the set of absolute content paths it claims.
+/// Built once per run (`build_content_path_index`) so that checking for cross-seeded/shared
+/// content doesn't re-query every download in the session for every torrent we reap.
+type ContentPathIndex = std::collections::HashMap<String, HashSet<std::path::PathBuf>>;
+
+/// Collect the absolute content paths claimed by every registered download in the "default" view.
+/// Querying the whole session is itself O(n) XMLRPC calls (one `f::PATH` multicall per
+/// multi-file download), so this is called once in `main` rather than once per reaped torrent.
+fn build_content_path_index(handle: &Server) -> Result<ContentPathIndex> {
+    let mut index = ContentPathIndex::new();
+
+    let query = d::MultiBuilder::new(handle, "default")
+        .call(d::HASH)
+        .call(d::BASE_PATH);
+
+    for (hash, base_path) in query.invoke()? {
+        let mut paths = HashSet::new();
+
+        let base_path = shellexpand::tilde(&base_path).into_owned();
+        let base = Path::new(&base_path);
+
+        // Single-file torrents: base_path *is* the content file.
+        if let Ok(meta) = std::fs::symlink_metadata(base) {
+            if !meta.file_type().is_dir() {
+                paths.insert(base.canonicalize().unwrap_or_else(|_| base.to_path_buf()));
+                index.insert(hash, paths);
+                continue;
+            }
+        }
+
+        // Multi-file torrents: base_path is the containing directory; enumerate content files.
+        let files = f::MultiBuilder::new(handle, &hash, None)
+            .call(f::PATH)
+            .invoke()?
+            .into_iter()
+            .map(|(path,)| path);
+        for file in files {
+            let abspath = base.join(file);
+            paths.insert(abspath.canonicalize().unwrap_or(abspath));
+        }
+
+        index.insert(hash, paths);
+    }
+
+    Ok(index)
+}
+
+/// The absolute content paths claimed by every registered download *other than* `exclude_hash`,
+/// read out of an index already built by `build_content_path_index`. Pure/local: no RPCs.
+fn other_downloads_content_paths(index: &ContentPathIndex, exclude_hash: &str) -> HashSet<std::path::PathBuf> {
+    index
+        .iter()
+        .filter(|(hash, _)| hash.as_str() != exclude_hash)
+        .flat_map(|(_, paths)| paths.iter().cloned())
+        .collect()
+}
+
+/// True if `path` should be left alone because another registered download still claims it:
+/// either it's hardlinked elsewhere (`st_nlink > 1`), or it appears in another download's file
+/// list.
+fn is_shared_content(path: &Path, shared: &HashSet<std::path::PathBuf>) -> std::io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    if shared.contains(path) {
+        return Ok(true);
+    }
+    let meta = std::fs::symlink_metadata(path)?;
+    Ok(meta.nlink() > 1)
+}
+
+/// A content file belonging to a download, along with enough chunk-completion information to
+/// tell whether it finished downloading.
+struct FileInfo {
+    path: String,
+    completed: i64,
+    size: i64,
+    size_bytes: i64,
+}
+
+impl FileInfo {
+    fn is_complete(&self) -> bool {
+        self.completed >= self.size
+    }
+}
+
+/// What `delete_from_filesystem` removed (or, under `--dry-run`, would have removed).
+struct DeletionPlan {
+    files: Vec<String>,
+    directories: Vec<String>,
+    total_bytes: u64,
+}
+
+impl DeletionPlan {
+    fn new() -> DeletionPlan {
+        DeletionPlan { files: Vec::new(), directories: Vec::new(), total_bytes: 0 }
+    }
+}
+
+/// Where a trashed path should land. Every torrent gets its own `trash_dir/<infohash>/` subtree
+/// (so distinct torrents can never collide, even if their content or .torrent files share a
+/// basename); `path`'s position relative to `content_root` (the torrent's content directory or
+/// single file) is mirrored under `<infohash>/content`, preserving the original folder structure.
+/// Paths outside `content_root` (the watched/session .torrent files) land under
+/// `<infohash>/torrents` instead.
+fn trash_destination(trash_dir: &Path, infohash: &str, content_root: &Path, path: &Path) -> std::path::PathBuf {
+    let base = trash_dir.join(infohash);
+    if path == content_root {
+        base.join("content")
+    } else if let Ok(rel) = path.strip_prefix(content_root) {
+        base.join("content").join(rel)
     } else {
-        println!("Ok.");
+        base.join("torrents").join(path.file_name().unwrap_or(path.as_os_str()))
     }
+}
+
+/// Record "now" as `infohash`'s trash time via a marker file inside its own
+/// `trash_dir/<infohash>/` subtree. `purge_trash` ages each torrent off of this marker rather than
+/// the shared `trash_dir`'s own mtime, which would otherwise get bumped forever by every other
+/// torrent's trash activity and never look old enough to purge.
+fn touch_trash_marker(trash_dir: &Path, infohash: &str) -> std::io::Result<()> {
+    let dir = trash_dir.join(infohash);
+    std::fs::create_dir_all(&dir)?;
+    std::fs::File::create(dir.join(".trashed_at"))?;
     Ok(())
 }
 
-/// Actually delete torrent-related files from the filesystem.
+/// Remove `path`, unless `dry_run` is set (a no-op) or `trash` is set, in which case `path` is
+/// relocated under the trash directory (preserving its position relative to `content_root`)
+/// instead of being unlinked.
+fn maybe_remove_file(path: &Path, dry_run: bool, trash: Option<(&Path, &str, &Path)>) -> std::io::Result<()> {
+    if dry_run {
+        return Ok(());
+    }
+    match trash {
+        Some((trash_dir, infohash, content_root)) => {
+            let dest = trash_destination(trash_dir, infohash, content_root, path);
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(path, dest)?;
+            touch_trash_marker(trash_dir, infohash)
+        }
+        None => std::fs::remove_file(path),
+    }
+}
+
+/// Remove the (expected-to-be-empty) directory `path`, unless `dry_run` is set. Directories are
+/// never trashed themselves: their content files are relocated individually (preserving
+/// structure under the trash directory), which empties the original directory for us to prune.
+fn maybe_remove_dir(path: &Path, dry_run: bool) -> std::io::Result<()> {
+    if dry_run { Ok(()) } else { std::fs::remove_dir(path) }
+}
+
+/// Actually delete torrent-related files from the filesystem, or, if `dry_run` is set, just
+/// compute and return the plan without touching anything. If `trash_dir` is set, "deletion" means
+/// relocating content there instead of unlinking it.
 ///
 /// Interacts with the filesystem, which could error if we do not have permissions to remove some
 /// file.
-fn delete_from_filesystem(watched: &str, session: &str, content: &str, files: &[String]) -> std::io::Result<()> {
+fn delete_from_filesystem(mode: DeleteMode, dry_run: bool, trash_dir: Option<&Path>, infohash: &str, watched: &str, session: &str, content: &str, files: &[FileInfo], shared: &HashSet<std::path::PathBuf>) -> std::io::Result<DeletionPlan> {
     let content = Path::new(content);
     assert!(content.is_absolute());
     let watched = Path::new(watched);
     let session = Path::new(session);
 
+    let mut plan = DeletionPlan::new();
+
+    // When trashing is enabled, every relocated path is positioned relative to `content`, under
+    // this torrent's own `<infohash>/` subtree, so the original directory structure survives the
+    // move without colliding with any other torrent's trashed content.
+    let trash = trash_dir.map(|dir| (dir, infohash, content));
+
     // lstat(2) the top-level file or directory associated with the download, so we can determine
     // if it is a symlink.
     let stat = std::fs::symlink_metadata(content)?;
     let content_type = stat.file_type();
 
-    // Start removing rtorrent download state files.
-    if watched.exists() {
-        std::fs::remove_file(watched)?;
+    // delete_partial_only leaves the watched/session .torrent files in place, since rtorrent needs
+    // them to keep tracking (and seeding) the content; the other two modes remove them.
+    if mode == DeleteMode::DeleteFiles || mode == DeleteMode::DeleteStateOnly {
+        if watched.exists() {
+            maybe_remove_file(watched, dry_run, trash)?;
+            plan.files.push(watched.display().to_string());
+        }
+        if session.exists() {
+            maybe_remove_file(session, dry_run, trash)?;
+            plan.files.push(session.display().to_string());
+        }
     }
-    if session.exists() {
-        std::fs::remove_file(session)?;
+
+    if mode == DeleteMode::DeleteStateOnly {
+        // Unregistering rtorrent's handle is all that was asked for; leave content on disk.
+        return Ok(plan);
     }
 
     // Don't recursively delete symlinked content.
     if content_type.is_symlink() {
-        return std::fs::remove_file(content);
+        if mode == DeleteMode::DeletePartialOnly && files.iter().all(FileInfo::is_complete) {
+            return Ok(plan);
+        }
+        if is_shared_content(content, shared)? {
+            println!("Keeping {}: shared with another registered download", content.display());
+            return Ok(plan);
+        }
+        maybe_remove_file(content, dry_run, trash)?;
+        plan.files.push(content.display().to_string());
+        // We only unlinked the symlink itself, not the (untouched) target it points at, so
+        // total_bytes should reflect the symlink's own size rather than the content tree's.
+        plan.total_bytes = stat.len();
+        return Ok(plan);
     } else if content_type.is_file() {
         // Single-file torrent case (or on-disk contents don't match the torrent's info).
         assert_eq!(files.len(), 1);
-        assert!(content.ends_with(&files[0]));
-        return std::fs::remove_file(content);
+        assert!(content.ends_with(&files[0].path));
+        if mode == DeleteMode::DeletePartialOnly && files[0].is_complete() {
+            return Ok(plan);
+        }
+        if is_shared_content(content, shared)? {
+            println!("Keeping {}: shared with another registered download", content.display());
+            return Ok(plan);
+        }
+        maybe_remove_file(content, dry_run, trash)?;
+        plan.files.push(content.display().to_string());
+        plan.total_bytes = files[0].size_bytes as u64;
+        return Ok(plan);
     }
 
     // Otherwise, this is a multi-file torrent.  Let's be somewhat careful to only delete files
@@ -124,18 +763,45 @@ fn delete_from_filesystem(watched: &str, session: &str, content: &str, files: &[
     // everything at the root of the name.
 
     // Collect any (implicit) subdirectories associated with this torrent, which will need cleaning
-    // up.
+    // up. Only directories containing a file we actually removed are candidates; this matters for
+    // delete_partial_only, where completed files (and thus their directories) may need to stay.
     let mut directories = HashSet::new();
+    // Directories that still hold a file we chose to keep (delete_partial_only, or shared
+    // content). A real rmdir on any of these (or an ancestor of these) would fail with
+    // DirectoryNotEmpty; dry_run has no filesystem to ask, so it consults this set instead.
+    let mut kept_dirs = HashSet::new();
+    let mut kept_any = false;
 
     // Start deleting content files, and track implicit subdirectories for cleanup in a second pass.
     for file in files {
-        let path = Path::new(file);
+        let path = Path::new(&file.path);
         // Seatbelt: we don't want to delete paths outside of `directory`.
         assert!(path.is_relative());
-        let abspath = content.join(path).canonicalize().unwrap();
+
+        if mode == DeleteMode::DeletePartialOnly && file.is_complete() {
+            // Keep finished files around; only reclaim space from incomplete ones.
+            kept_any = true;
+            for ancestor in path.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
+                kept_dirs.insert(ancestor);
+            }
+            continue;
+        }
+
+        let abspath = content.join(path).canonicalize()?;
         assert!(abspath.starts_with(content));
 
-        std::fs::remove_file(abspath)?;
+        if is_shared_content(&abspath, shared)? {
+            println!("Keeping {}: shared with another registered download", abspath.display());
+            kept_any = true;
+            for ancestor in path.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
+                kept_dirs.insert(ancestor);
+            }
+            continue;
+        }
+
+        maybe_remove_file(&abspath, dry_run, trash)?;
+        plan.files.push(abspath.display().to_string());
+        plan.total_bytes += file.size_bytes as u64;
 
         // Iterate subdirectories implied by this content file and add them to the set.
         for ancestor in path.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
@@ -148,15 +814,94 @@ fn delete_from_filesystem(watched: &str, session: &str, content: &str, files: &[
     // parents.
     directories.sort_unstable_by_key(|d| -(d.as_os_str().len() as isize));
 
-    // Delete implicit subdirectories.
+    // Delete implicit subdirectories.  If we kept some file back (delete_partial_only, or shared
+    // content), a directory may still hold it, so a non-empty-directory error there just means
+    // "nothing more to do" rather than a real failure.  Under dry_run there's no filesystem to ask,
+    // so `kept_dirs` tells us the same thing: skip (rather than report as removed) any directory
+    // that still holds a file we chose to keep.
     for dir in directories {
-        let abspath = content.join(dir).canonicalize().unwrap();
+        if dry_run && kept_dirs.contains(dir) {
+            continue;
+        }
+
+        let abspath = content.join(dir).canonicalize()?;
         assert!(abspath.starts_with(content));
 
-        std::fs::remove_dir(abspath)?;
+        if let Err(e) = maybe_remove_dir(&abspath, dry_run) {
+            if !kept_any || e.kind() != std::io::ErrorKind::DirectoryNotEmpty {
+                return Err(e);
+            }
+            continue;
+        }
+        plan.directories.push(abspath.display().to_string());
+    }
+
+    if kept_any {
+        // Only the containing directory itself might still hold content we kept, so a
+        // "directory not empty" error here just means "nothing more to do" — but any other error
+        // (e.g. a permission problem) is real and must be propagated, not swallowed. Under dry_run
+        // there's nothing to ask the filesystem, but kept_any already tells us some descendant
+        // survives, so `content` itself can never be considered removed either.
+        if !dry_run {
+            match maybe_remove_dir(content, dry_run) {
+                Ok(()) => plan.directories.push(content.display().to_string()),
+                Err(e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => {}
+                Err(e) => return Err(e),
+            }
+        }
+        return Ok(plan);
     }
 
     // Finally, prune the containing directory.
-    std::fs::remove_dir(content)?;
+    maybe_remove_dir(content, dry_run)?;
+    plan.directories.push(content.display().to_string());
+    Ok(plan)
+}
+
+/// Purge each per-torrent `trash_dir/<infohash>/` entry whose `.trashed_at` marker (see
+/// `touch_trash_marker`) is older than `retention`, giving trashed content an undo window without
+/// letting the trash grow forever. Entries with no marker (e.g. hand-placed files, or trash from
+/// before this marker existed) fall back to their own mtime. Best-effort: a single entry that
+/// fails to stat or remove is logged and skipped rather than aborting the whole purge. Under
+/// `dry_run`, still walks and logs what would be purged, but removes nothing.
+fn purge_trash(trash_dir: &Path, retention: std::time::Duration, dry_run: bool) -> std::io::Result<()> {
+    let entries = match std::fs::read_dir(trash_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let now = std::time::SystemTime::now();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+
+        let marker = path.join(".trashed_at");
+        let stat_path: &Path = if marker.is_file() { &marker } else { &path };
+
+        let age = match std::fs::metadata(stat_path).and_then(|m| m.modified()).and_then(|t| {
+            now.duration_since(t).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        }) {
+            Ok(age) => age,
+            Err(e) => {
+                println!("Skipping trash entry {}: couldn't determine age: {}", path.display(), e);
+                continue;
+            }
+        };
+        if age < retention {
+            continue;
+        }
+
+        if dry_run {
+            println!("Would purge old trash entry {}", path.display());
+            continue;
+        }
+
+        let result = if path.is_dir() { std::fs::remove_dir_all(&path) } else { std::fs::remove_file(&path) };
+        if let Err(e) = result {
+            println!("Failed to purge old trash entry {}: {}", path.display(), e);
+        }
+    }
+
     Ok(())
 }